@@ -1,121 +1,790 @@
-use flate2::read::ZlibDecoder;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 use ini::Ini;
 use sha1::{Sha1, Digest};
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     fs,
+    io::{BufRead, Read, Write},
     str,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-trait GitObject {
+pub trait GitObject {
     fn serialize(&self) -> Vec<u8>;
-    fn deserialize(&mut self, data: Vec<u8>);
+    fn deserialize(&mut self, data: Vec<u8>) -> Result<(), String>;
     fn fmt(&self) -> &[u8];
 }
 
-struct GitBlob {
-    blobdata: Vec<u8>,
+pub struct GitBlob {
+    pub blobdata: Vec<u8>,
 }
 
 impl GitObject for GitBlob {
     fn serialize(&self) -> Vec<u8> {
-        return self.blobdata.to_owned();
+        self.blobdata.to_owned()
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
+    fn deserialize(&mut self, data: Vec<u8>) -> Result<(), String> {
         self.blobdata = data;
+        Ok(())
     }
 
     fn fmt(&self) -> &[u8] {
-        return b"blob";
+        b"blob"
     }
 }
 
-enum GitObjects {
-    Commit(),
-    Tree(),
-    Tag(),
-    Blob(),
+#[derive(Clone, Debug, PartialEq)]
+pub struct GitTreeLeaf {
+    pub mode: String,
+    // Raw bytes: git doesn't require tree paths to be valid UTF-8.
+    pub path: Vec<u8>,
+    pub sha: [u8; 20],
 }
 
-fn object_read(repo: &GitRepository, sha: &str) -> Result<GitObjects, String> {
-    let path = repo_file(repo, vec!["objects", &sha[0..2], &sha[2..]], false)?;
+impl GitTreeLeaf {
+    pub fn sha_hex(&self) -> String {
+        self.sha.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn sha_from_hex(hex: &str) -> Option<[u8; 20]> {
+        if hex.len() != 40 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let mut sha = [0; 20];
+        for (i, byte) in sha.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(sha)
+    }
+
+    // Git orders directories as if their name had a trailing slash.
+    fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.path.clone();
+        if self.mode == "40000" || self.mode == "040000" {
+            key.push(b'/');
+        }
+        key
+    }
+}
+
+#[derive(Debug)]
+pub struct TreeParseError {
+    pub offset: usize,
+    pub reason: String,
+    pub entries: Vec<GitTreeLeaf>,
+}
+
+impl std::fmt::Display for TreeParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Malformed tree at byte {}: {} ({} entries decoded)",
+            self.offset,
+            self.reason,
+            self.entries.len()
+        )
+    }
+}
+
+fn tree_parse_one(raw: &[u8], start: usize) -> Result<(usize, GitTreeLeaf), (usize, String)> {
+    let space = match raw[start..].iter().position(|&x| x == b' ') {
+        Some(p) => start + p,
+        None => return Err((start, "missing space after mode".to_string())),
+    };
+    let mode = &raw[start..space];
+    if mode.is_empty() || !mode.iter().all(|x| (b'0'..=b'7').contains(x)) {
+        return Err((start, "invalid mode".to_string()));
+    }
+
+    let null = match raw[space + 1..].iter().position(|&x| x == b'\x00') {
+        Some(p) => space + 1 + p,
+        None => return Err((space + 1, "missing null terminator after path".to_string())),
+    };
+    let path = &raw[space + 1..null];
+    if path.is_empty() {
+        return Err((space + 1, "empty path".to_string()));
+    }
+
+    let end = null + 1 + 20;
+    if end > raw.len() {
+        return Err((
+            null + 1,
+            format!("truncated sha ({} of 20 bytes)", raw.len() - null - 1),
+        ));
+    }
+    let mut sha = [0; 20];
+    sha.copy_from_slice(&raw[null + 1..end]);
+
+    let leaf = GitTreeLeaf {
+        mode: str::from_utf8(mode).unwrap().to_string(),
+        path: path.to_vec(),
+        sha,
+    };
+    Ok((end, leaf))
+}
+
+// Never indexes past the end of `raw`: a malformed tree reports the byte
+// offset it choked on together with every entry decoded before it.
+pub fn tree_parse(raw: &[u8]) -> Result<Vec<GitTreeLeaf>, TreeParseError> {
+    let mut pos = 0;
+    let mut entries = Vec::new();
+    while pos < raw.len() {
+        match tree_parse_one(raw, pos) {
+            Ok((next, leaf)) => {
+                pos = next;
+                entries.push(leaf);
+            }
+            Err((offset, reason)) => {
+                return Err(TreeParseError {
+                    offset,
+                    reason,
+                    entries,
+                })
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub struct GitTree {
+    pub items: Vec<GitTreeLeaf>,
+}
+
+impl GitObject for GitTree {
+    fn serialize(&self) -> Vec<u8> {
+        let mut items = self.items.clone();
+        items.sort_by_key(|leaf| leaf.sort_key());
+
+        let mut ret = Vec::new();
+        for leaf in items {
+            ret.extend_from_slice(leaf.mode.as_bytes());
+            ret.push(b' ');
+            ret.extend_from_slice(&leaf.path);
+            ret.push(b'\x00');
+            ret.extend_from_slice(&leaf.sha);
+        }
+        ret
+    }
+
+    fn deserialize(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.items = tree_parse(&data).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn fmt(&self) -> &[u8] {
+        b"tree"
+    }
+}
+
+// Key-value list with message, the format shared by commits and tags.
+// Headers keep their original order so serialization is byte-identical.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Kvlm {
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub message: Vec<u8>,
+}
+
+impl Kvlm {
+    pub fn get(&self, key: &str) -> Vec<&[u8]> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_slice())
+            .collect()
+    }
+}
+
+pub fn kvlm_parse(raw: &[u8]) -> Result<Kvlm, String> {
+    let mut kvlm = Kvlm::default();
+    let mut pos = 0;
+
+    loop {
+        let spc = raw[pos..].iter().position(|&x| x == b' ').map(|p| pos + p);
+        let nl = raw[pos..].iter().position(|&x| x == b'\n').map(|p| pos + p);
+
+        // A blank line (or no more headers) starts the message.
+        match (spc, nl) {
+            (_, Some(nl)) if nl == pos => {
+                kvlm.message = raw[pos + 1..].to_vec();
+                return Ok(kvlm);
+            }
+            (Some(spc), Some(nl)) if spc < nl => {}
+            _ => return Err(format!("Malformed header at byte {}", pos)),
+        }
+        let spc = spc.unwrap();
+
+        // Continuation lines start with a space; the value ends at the
+        // first newline that isn't followed by one.
+        let mut end = spc;
+        loop {
+            end = match raw[end + 1..].iter().position(|&x| x == b'\n') {
+                Some(p) => end + 1 + p,
+                None => return Err(format!("Unterminated header at byte {}", pos)),
+            };
+            if raw.get(end + 1) != Some(&b' ') {
+                break;
+            }
+        }
+
+        let key = String::from_utf8_lossy(&raw[pos..spc]).to_string();
+        let value = kvlm_unfold(&raw[spc + 1..end]);
+        kvlm.headers.push((key, value));
+        pos = end + 1;
+    }
+}
+
+fn kvlm_unfold(value: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        ret.push(value[i]);
+        if value[i] == b'\n' && value.get(i + 1) == Some(&b' ') {
+            i += 1;
+        }
+        i += 1;
+    }
+    ret
+}
+
+pub fn kvlm_serialize(kvlm: &Kvlm) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for (key, value) in &kvlm.headers {
+        ret.extend_from_slice(key.as_bytes());
+        ret.push(b' ');
+        for &b in value {
+            ret.push(b);
+            if b == b'\n' {
+                ret.push(b' ');
+            }
+        }
+        ret.push(b'\n');
+    }
+    ret.push(b'\n');
+    ret.extend_from_slice(&kvlm.message);
+    ret
+}
+
+pub struct GitCommit {
+    pub kvlm: Kvlm,
+}
+
+impl GitCommit {
+    // Tag objects embedded by merging a (signed) tag, one per merged tag.
+    pub fn mergetags(&self) -> Vec<String> {
+        self.kvlm
+            .get("mergetag")
+            .into_iter()
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect()
+    }
+}
+
+impl GitObject for GitCommit {
+    fn serialize(&self) -> Vec<u8> {
+        kvlm_serialize(&self.kvlm)
+    }
+
+    fn deserialize(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.kvlm = kvlm_parse(&data)?;
+        Ok(())
+    }
+
+    fn fmt(&self) -> &[u8] {
+        b"commit"
+    }
+}
+
+pub struct GitTag {
+    pub kvlm: Kvlm,
+}
+
+impl GitObject for GitTag {
+    fn serialize(&self) -> Vec<u8> {
+        kvlm_serialize(&self.kvlm)
+    }
 
-    let raw_data = fs::read(path).unwrap();
+    fn deserialize(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.kvlm = kvlm_parse(&data)?;
+        Ok(())
+    }
+
+    fn fmt(&self) -> &[u8] {
+        b"tag"
+    }
+}
+
+pub enum GitObjects {
+    Commit(GitCommit),
+    Tree(GitTree),
+    Tag(GitTag),
+    Blob(GitBlob),
+}
+
+pub fn object_read(repo: &GitRepository, sha: &str) -> Result<GitObjects, String> {
+    object_read_limited(repo, sha, usize::MAX)
+}
+
+// Inflates the object a piece at a time and gives up as soon as it is known
+// to be larger than `max_bytes`, so untrusted objects can't balloon memory.
+pub fn object_read_limited(repo: &GitRepository, sha: &str, max_bytes: usize) -> Result<GitObjects, String> {
+    let (mut decoder, fmt, size) = object_open(repo, sha)?;
+    if size > max_bytes {
+        return Err(format!(
+            "Object {} is {} bytes, over the limit of {} bytes",
+            sha, size, max_bytes
+        ));
+    }
+
+    // The header may lie, so never inflate more than one byte past it.
+    let mut data = Vec::new();
+    (&mut decoder)
+        .take(size as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Malformed object {}: {}", sha, e))?;
+    if data.len() != size {
+        return Err(format!("Malformed object {}: bad length", sha));
+    }
+
+    let malformed = |e: String| format!("Malformed object {}: {}", sha, e);
+    match fmt.as_str() {
+        "commit" => {
+            let mut obj = GitCommit { kvlm: Kvlm::default() };
+            obj.deserialize(data).map_err(malformed)?;
+            Ok(GitObjects::Commit(obj))
+        }
+        "tree" => {
+            let mut obj = GitTree { items: Vec::new() };
+            obj.deserialize(data).map_err(malformed)?;
+            Ok(GitObjects::Tree(obj))
+        }
+        "tag" => {
+            let mut obj = GitTag { kvlm: Kvlm::default() };
+            obj.deserialize(data).map_err(malformed)?;
+            Ok(GitObjects::Tag(obj))
+        }
+        "blob" => Ok(GitObjects::Blob(GitBlob { blobdata: data })),
+        _ => Err(format!("Unknown type {} for object {}", fmt, sha)),
+    }
+}
+
+// The type of an object, read from its header alone.
+fn object_type(repo: &GitRepository, sha: &str) -> Result<String, String> {
+    object_open(repo, sha).map(|(_, fmt, _)| fmt)
+}
 
-    let decoder = ZlibDecoder::new(raw_data.as_slice());
-    let decoded_data = decoder.get_ref();
+// Opens an object and inflates just its header, returning the decoder
+// positioned at the data along with the type and size the header claims.
+fn object_open(repo: &GitRepository, sha: &str) -> Result<(ZlibDecoder<fs::File>, String, usize), String> {
+    // The sha may come from a remote too, so never let it pick the path.
+    if sha.len() != 40 || !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid object name {}", sha));
+    }
+    let path = repo.objects_dir().join(&sha[0..2]).join(&sha[2..]);
+    let file = fs::File::open(path).map_err(|e| format!("Cannot read object {}: {}", sha, e))?;
+    let mut decoder = ZlibDecoder::new(file);
 
-    let fmt_end = match decoded_data.iter().position(|&x| x == b' ') {
+    let mut header = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        if decoder.read_exact(&mut byte).is_err() || header.len() > 32 {
+            return Err(format!("Malformed object {}: Cannot read 'size'", sha));
+        }
+        if byte[0] == b'\x00' {
+            break;
+        }
+        header.push(byte[0]);
+    }
+
+    let fmt_end = match header.iter().position(|&x| x == b' ') {
         Some(p) => p,
         None => return Err(format!("Malformed object {}: Cannot read 'fmt'", sha)),
-};
-    let fmt = &decoded_data[..fmt_end];
+    };
+    let fmt = String::from_utf8_lossy(&header[..fmt_end]).to_string();
 
-    let size_end = match decoded_data.iter().position(|&x| x == b'\x00') {
-        Some(p) => p,
-        None => return  Err(format!("Malformed object {}: Cannot read 'size'", sha)),
+    let size: usize = match str::from_utf8(&header[fmt_end + 1..]).map(|s| s.parse()) {
+        Ok(Ok(size)) => size,
+        _ => return Err(format!("Malformed object {}: Cannot read 'size'", sha)),
     };
-    let size = str::from_utf8(&decoded_data[fmt_end..size_end]).unwrap();
-    let size: usize = size.parse().unwrap();
-    if size != decoded_data.len() - size_end - 1 {
-        return Err(format!("Malformed object {}: bad length", sha));
+
+    Ok((decoder, fmt, size))
+}
+
+pub fn object_exists(repo: &GitRepository, sha: &str) -> bool {
+    sha.len() == 40
+        && sha.bytes().all(|b| b.is_ascii_hexdigit())
+        && repo.objects_dir().join(&sha[0..2]).join(&sha[2..]).is_file()
+}
+
+// Resolves a name to an object sha. With `fmt`, the object must be of that
+// type; with `follow`, tags are peeled and commits dereferenced to their tree
+// until it is.
+pub fn object_find(repo: &GitRepository, name: &str, fmt: Option<&str>, follow: bool) -> Result<String, String> {
+    let mut sha = object_resolve(repo, name)?;
+    let fmt = match fmt {
+        Some(fmt) => fmt,
+        None => return Ok(sha),
+    };
+
+    loop {
+        let (kind, next) = match object_read(repo, &sha)? {
+            GitObjects::Tag(tag) => ("tag", tag.kvlm.get("object").first().map(|v| v.to_vec())),
+            GitObjects::Commit(commit) if fmt == "tree" => {
+                ("commit", commit.kvlm.get("tree").first().map(|v| v.to_vec()))
+            }
+            GitObjects::Commit(_) => ("commit", None),
+            GitObjects::Tree(_) => ("tree", None),
+            GitObjects::Blob(_) => ("blob", None),
+        };
+
+        if kind == fmt {
+            return Ok(sha);
+        }
+
+        match next {
+            Some(next) if follow => sha = String::from_utf8_lossy(&next).to_string(),
+            _ => return Err(format!("{} is a {}, not a {}", name, kind, fmt)),
+        }
+    }
+}
+
+// Resolves a name (HEAD, a full sha, or a ref) to an object sha. Refs are
+// tried in git's order.
+fn object_resolve(repo: &GitRepository, name: &str) -> Result<String, String> {
+    let is_hex = !name.is_empty() && name.bytes().all(|b| b.is_ascii_hexdigit());
+    if is_hex && name.len() == 40 {
+        return Ok(name.to_lowercase());
+    }
+
+    if name == "HEAD" {
+        return ref_resolve(repo, "HEAD")?.ok_or_else(|| "HEAD does not point to a commit yet".to_string());
     }
 
-    match fmt {
-        b"commit" => {}
-        b"tree" => {}
-        b"tag" => {}
-        b"blob" => {}
+    let candidates = if name.starts_with("refs/") {
+        vec![name.to_string()]
+    } else {
+        ["refs/", "refs/tags/", "refs/heads/", "refs/remotes/"]
+            .iter()
+            .map(|prefix| format!("{}{}", prefix, name))
+            .collect()
+    };
+    for candidate in candidates {
+        if let Some(sha) = ref_resolve(repo, &candidate)? {
+            return Ok(sha);
+        }
     }
 
-    return Err(format!("Unknown type {:?} for object {}", fmt, sha));
+    Err(format!("No such reference {}", name))
 }
 
-fn object_find<'a>(repo: &GitRepository, name: &'a str, fmt: &str, follow: bool) -> &'a str {
-    return name
+pub fn object_write(repo: &GitRepository, obj: &dyn GitObject, actually_write: bool) -> Result<String, String> {
+    let mut raw = Vec::new();
+    let sha = object_hash(obj, &mut raw);
+
+    if actually_write {
+        let dir = dir_ensure(repo, repo.objects_dir().join(&sha[0..2]), actually_write)?;
+        object_store(repo, &dir.join(&sha[2..]), &object_compress(&raw)?)?;
+    }
+
+    Ok(sha)
 }
 
-fn object_write(obj: &GitObject, actually_write: bool) {
+// Fills `raw` with the object as stored ("<fmt> <size>\0<data>") and returns its sha.
+fn object_hash(obj: &dyn GitObject, raw: &mut Vec<u8>) -> String {
     let data = obj.serialize();
-    let result = format!("{:?} {}\x00{}", obj.fmt(), data.len(), data);
+    raw.clear();
+    raw.extend_from_slice(obj.fmt());
+    raw.extend_from_slice(format!(" {}\x00", data.len()).as_bytes());
+    raw.extend_from_slice(&data);
+
     let mut sha1 = Sha1::default();
-    sha1.input(result);
-    let sha = sha1.result();
+    sha1.input(&raw[..]);
+    format!("{:x}", sha1.result())
+}
 
-    if actually_write {
-        let path = repo_file(obj.repo, vec!["objects", sha[0..2], sha[2..]], actually_write);
+fn object_compress(raw: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(raw).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+// Objects are immutable, so one that is already there is left alone. New ones
+// are written to a temporary file and renamed into place, so a crash never
+// leaves a half-written object under its real name.
+fn object_store(repo: &GitRepository, path: &Path, compressed: &[u8]) -> Result<(), String> {
+    object_store_with(repo, path, compressed, |tmp| fs::File::create(tmp))
+}
+
+// object_store, with the temporary file opened by `create`.
+fn object_store_with<W, F>(repo: &GitRepository, path: &Path, compressed: &[u8], create: F) -> Result<(), String>
+where
+    W: SyncWrite,
+    F: FnOnce(&Path) -> std::io::Result<W>,
+{
+    if path.exists() {
+        return Ok(());
+    }
+
+    static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = path.parent().ok_or_else(|| format!("Invalid object path {}", path.display()))?;
+    let tmp = dir.join(format!(
+        "tmp_obj_{}_{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let fsync = repo_fsync(repo);
+    let written = create(&tmp)
+        .map_err(|e| e.to_string())
+        .and_then(|mut file| file_write(&mut file, compressed, fsync))
+        .and_then(|_| shared_perm_add(&tmp, repo_shared(repo).file_bits()))
+        .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("Cannot write object {}: {}", path.display(), e));
+    }
+
+    // Make the rename itself durable too.
+    if fsync {
+        fs::File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+// Collects objects for bulk imports and writes them all in `flush`, checking
+// each objects/xx directory once instead of once per object. Nothing reaches
+// the disk until `flush` is called; dropping the writer flushes whatever is
+// left, but can only swallow errors, so call `flush` to see them.
+pub struct BatchWriter<'a> {
+    repo: &'a GitRepository,
+    raw: Vec<u8>,
+    pending: Vec<(String, Vec<u8>)>,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(repo: &'a GitRepository) -> BatchWriter<'a> {
+        BatchWriter {
+            repo,
+            raw: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, obj: &dyn GitObject) -> Result<String, String> {
+        let sha = object_hash(obj, &mut self.raw);
+        let compressed = object_compress(&self.raw)?;
+        self.pending.push((sha.clone(), compressed));
+        Ok(sha)
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        pending.dedup_by(|a, b| a.0 == b.0);
+
+        let mut dir = PathBuf::new();
+        for (sha, compressed) in pending {
+            if !dir.ends_with(&sha[0..2]) {
+                dir = dir_ensure(self.repo, self.repo.objects_dir().join(&sha[0..2]), true)?;
+            }
+
+            object_store(self.repo, &dir.join(&sha[2..]), &compressed)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for BatchWriter<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Anything written through this can be asked to hit the disk before we return.
+trait SyncWrite: Write {
+    fn sync(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncWrite for fs::File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_all()
+    }
+}
+
+fn file_write(file: &mut dyn SyncWrite, data: &[u8], fsync: bool) -> Result<(), String> {
+    file.write_all(data).map_err(|e| e.to_string())?;
+    if fsync {
+        file.sync().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// core.fsyncObjectFiles makes object writes durable. Off by default, as in git.
+fn repo_fsync(repo: &GitRepository) -> bool {
+    match config_get(&repo.conf, "core", "fsyncObjectFiles") {
+        Some(v) => config_bool(v),
+        None => false,
+    }
+}
+
+fn repo_bare(repo: &GitRepository) -> bool {
+    match config_get(&repo.conf, "core", "bare") {
+        Some(v) => config_bool(v),
+        None => false,
+    }
+}
+
+// Git config keys are case-insensitive, ini keys are not.
+fn config_get<'a>(conf: &'a Ini, section: &str, key: &str) -> Option<&'a str> {
+    conf.section(Some(section))?
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+}
+
+fn config_bool(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "true" | "yes" | "on" | "1")
+}
+
+// core.sharedRepository, as set by `init --shared`.
+#[derive(Clone, Copy, PartialEq)]
+enum SharedMode {
+    Umask,
+    Group,
+    All,
+}
+
+impl SharedMode {
+    fn parse(value: &str) -> Result<SharedMode, String> {
+        match value.to_lowercase().as_str() {
+            "" | "0" | "false" | "umask" => Ok(SharedMode::Umask),
+            "1" | "true" | "group" => Ok(SharedMode::Group),
+            "2" | "all" | "world" | "everybody" => Ok(SharedMode::All),
+            _ => Err(format!("Unsupported sharedRepository value {}", value)),
+        }
+    }
+
+    fn config_value(self) -> &'static str {
+        match self {
+            SharedMode::Umask => "0",
+            SharedMode::Group => "1",
+            SharedMode::All => "2",
+        }
+    }
+
+    // Directories also get setgid so new entries keep the repository's group.
+    fn dir_bits(self) -> u32 {
+        match self {
+            SharedMode::Umask => 0,
+            SharedMode::Group => 0o2070,
+            SharedMode::All => 0o2075,
+        }
+    }
+
+    // Loose objects are never modified, so they only need to be readable.
+    fn file_bits(self) -> u32 {
+        match self {
+            SharedMode::Umask => 0,
+            SharedMode::Group => 0o040,
+            SharedMode::All => 0o044,
+        }
+    }
+
+    // HEAD, config and the like are rewritten, so the group may write them.
+    fn writable_file_bits(self) -> u32 {
+        match self {
+            SharedMode::Umask => 0,
+            SharedMode::Group => 0o060,
+            SharedMode::All => 0o064,
+        }
+    }
+}
+
+fn repo_shared(repo: &GitRepository) -> SharedMode {
+    match config_get(&repo.conf, "core", "sharedRepository") {
+        Some(v) => SharedMode::parse(v).unwrap_or(SharedMode::Umask),
+        None => SharedMode::Umask,
     }
 }
 
-pub struct GitRepository<'a> {
-    pub worktree: &'a Path,
+#[cfg(unix)]
+fn shared_perm_add(path: &Path, bits: u32) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if bits == 0 {
+        return Ok(());
+    }
+
+    let mut perms = fs::metadata(path).map_err(|e| e.to_string())?.permissions();
+    perms.set_mode(perms.mode() | bits);
+    fs::set_permissions(path, perms).map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn shared_perm_add(_path: &Path, _bits: u32) -> Result<(), String> {
+    Ok(())
+}
+
+pub struct GitRepository {
+    // None for a bare repository.
+    pub worktree: Option<PathBuf>,
     pub gitdir: PathBuf,
     pub conf: Ini,
+    // What tags peel to, by tag sha; see ref_peel.
+    peel_cache: RefCell<HashMap<String, String>>,
 }
 
-impl<'a> GitRepository<'a> {
-    pub fn new(path: &str, force: bool) -> Result<GitRepository, String> {
-        let worktree = Path::new(path);
+impl GitRepository {
+    pub fn new<P: AsRef<Path>>(path: P, force: bool) -> Result<GitRepository, String> {
+        let worktree = path.as_ref().to_path_buf();
         let gitdir = worktree.join(".git");
+        GitRepository::load(Some(worktree), gitdir, force)
+    }
 
+    // Opens the repository whose git directory is given explicitly, as with
+    // --git-dir or GIT_DIR. A bare one has no worktree; otherwise, like git
+    // without core.worktree, the worktree is the current directory.
+    pub fn with_gitdir<P: AsRef<Path>>(gitdir: P) -> Result<GitRepository, String> {
+        let gitdir = fs::canonicalize(gitdir.as_ref())
+            .map_err(|_| format!("Not a git repository: {}", gitdir.as_ref().display()))?;
+        let mut repo = GitRepository::load(None, gitdir, false)?;
+        if !repo_bare(&repo) {
+            repo.worktree = Some(std::env::current_dir().map_err(|e| e.to_string())?);
+        }
+        Ok(repo)
+    }
+
+    fn load(worktree: Option<PathBuf>, gitdir: PathBuf, force: bool) -> Result<GitRepository, String> {
         if !(force || gitdir.is_dir()) {
-            return Err(format!("Not a Git repository {}", path));
+            let path = worktree.as_ref().unwrap_or(&gitdir);
+            return Err(format!("Not a Git repository {}", path.display()));
         }
 
         let mut conf = Ini::new();
 
         let path = gitdir.join("config");
         if path.exists() {
-            conf = Ini::load_from_file(path).unwrap();
+            conf = Ini::load_from_file(&path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
         } else if !force {
             return Err("Configuration file missing".to_string());
         }
 
         if !force {
             let mut core = conf.with_section(Some("core"));
-            let vers = core.get("repositoryformatversion").unwrap();
-            let vers: u32 = vers.parse().unwrap();
+            let vers = core
+                .get("repositoryformatversion")
+                .ok_or_else(|| "Configuration has no core.repositoryformatversion".to_string())?;
+            let vers: u32 = vers
+                .parse()
+                .map_err(|_| format!("Invalid repositoryformatversion {}", vers))?;
             if vers != 0 {
                 return Err(format!("Unsupported repositoryformatversion {}", vers));
             }
@@ -125,59 +794,127 @@ impl<'a> GitRepository<'a> {
             worktree,
             gitdir,
             conf,
+            peel_cache: RefCell::new(HashMap::new()),
         })
     }
 
-    pub fn repo_create(path: &str) -> Result<GitRepository, String> {
-        let repo = GitRepository::new(path, true)?;
+    pub fn repo_create(path: &str, shared: Option<&str>) -> Result<GitRepository, String> {
+        let mut repo = GitRepository::new(path, true)?;
+        let shared = match shared {
+            Some(value) => SharedMode::parse(value)?,
+            None => SharedMode::Umask,
+        };
+        repo.conf = GitRepository::repo_default_config(shared);
 
-        if repo.worktree.exists() {
-            if !repo.worktree.is_dir() {
+        let worktree = Path::new(path);
+        if worktree.exists() {
+            if !worktree.is_dir() {
                 return Err(format!("{} is not a directory!", path));
             }
-            if fs::read_dir(repo.worktree).unwrap().count() > 0 {
+            if fs::read_dir(worktree).map_err(|e| e.to_string())?.count() > 0 {
                 return Err(format!("{} is not empty!", path));
             }
         } else {
-            fs::create_dir_all(repo.worktree).unwrap();
+            fs::create_dir_all(worktree).map_err(|e| e.to_string())?;
         }
 
         repo_dir(&repo, vec!["branches"], true)?;
-        repo_dir(&repo, vec!["objects"], true)?;
-        repo_dir(&repo, vec!["refs", "tags"], true)?;
-        repo_dir(&repo, vec!["refs", "heads"], true)?;
+        dir_ensure(&repo, repo.objects_dir(), true)?;
+        dir_ensure(&repo, repo.refs_dir().join("tags"), true)?;
+        dir_ensure(&repo, repo.refs_dir().join("heads"), true)?;
 
+        let description = repo_file(&repo, vec!["description"], false)?;
         fs::write(
-            repo_file(&repo, vec!["description"], false).unwrap(),
+            &description,
             "Unnamed repository; edit this file 'description' to name the repository.",
         )
-        .unwrap();
+        .map_err(|e| e.to_string())?;
 
-        fs::write(
-            repo_file(&repo, vec!["HEAD"], false).unwrap(),
-            "ref: refs/heads/master\n",
-        )
-        .unwrap();
+        let head = repo_file(&repo, vec!["HEAD"], false)?;
+        fs::write(&head, "ref: refs/heads/master\n").map_err(|e| e.to_string())?;
 
-        GitRepository::repo_default_config()
-            .write_to_file(repo_file(&repo, vec!["config"], false).unwrap())
-            .unwrap();
+        let config = repo_file(&repo, vec!["config"], false)?;
+        config_write(&repo.conf, config.clone())?;
+
+        for path in [description, head, config].iter() {
+            shared_perm_add(path, shared.writable_file_bits())?;
+        }
 
         Ok(repo)
     }
 
-    fn repo_default_config() -> Ini {
+    // Where objects and refs live. Everything else derives these paths from
+    // here rather than joining onto gitdir by hand.
+    pub fn objects_dir(&self) -> PathBuf {
+        self.gitdir.join("objects")
+    }
+
+    pub fn refs_dir(&self) -> PathBuf {
+        self.gitdir.join("refs")
+    }
+
+    fn repo_default_config(shared: SharedMode) -> Ini {
         let mut conf = Ini::new();
 
+        // The same keys, in the same order, as `git init` on this platform.
         conf.with_section(Some("core"))
             .set("repositoryformatversion", "0")
-            .set("filemode", "false")
-            .set("bare", "false");
+            .set("filemode", if cfg!(unix) { "true" } else { "false" })
+            .set("bare", "false")
+            .set("logallrefupdates", "true");
+
+        // Like git, a shared repository also refuses history rewrites on push.
+        if shared != SharedMode::Umask {
+            conf.with_section(Some("core"))
+                .set("sharedrepository", shared.config_value());
+            conf.with_section(Some("receive"))
+                .set("denyNonFastforwards", "true");
+        }
 
         conf
     }
 }
 
+// Writes the config the way git lays it out: `[section]` headers with
+// tab-indented `key = value` lines, in insertion order.
+fn config_write(conf: &Ini, path: PathBuf) -> Result<(), String> {
+    let mut out = String::new();
+    for (section, props) in conf.iter() {
+        if let Some(name) = section {
+            out.push_str(&format!("[{}]\n", name));
+        }
+        for (key, value) in props.iter() {
+            out.push_str(&format!("\t{} = {}\n", key, config_escape(value)));
+        }
+    }
+
+    fs::write(path, out).map_err(|e| e.to_string())
+}
+
+// Quotes and escapes a value the way git writes it back.
+fn config_escape(value: &str) -> String {
+    let mut out = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+
+    let needs_quotes = value.starts_with(' ')
+        || value.ends_with(' ')
+        || value.contains(';')
+        || value.contains('#');
+    if needs_quotes {
+        format!("\"{}\"", out)
+    } else {
+        out
+    }
+}
+
 fn repo_path(repo: &GitRepository, paths: Vec<&str>) -> PathBuf {
     let mut p = repo.gitdir.to_path_buf();
     for path in paths {
@@ -194,8 +931,10 @@ fn repo_file(repo: &GitRepository, paths: Vec<&str>, mkdir: bool) -> Result<Path
 }
 
 fn repo_dir(repo: &GitRepository, paths: Vec<&str>, mkdir: bool) -> Result<PathBuf, String> {
-    let path = repo_path(repo, paths);
+    dir_ensure(repo, repo_path(repo, paths), mkdir)
+}
 
+fn dir_ensure(repo: &GitRepository, path: PathBuf, mkdir: bool) -> Result<PathBuf, String> {
     if path.exists() {
         return if path.is_dir() {
             Ok(path)
@@ -205,24 +944,871 @@ fn repo_dir(repo: &GitRepository, paths: Vec<&str>, mkdir: bool) -> Result<PathB
     }
 
     if mkdir {
-        fs::create_dir_all(&path).unwrap();
+        fs::create_dir_all(&path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+
+        let bits = repo_shared(repo).dir_bits();
+        let mut dir = path.as_path();
+        while dir.starts_with(&repo.gitdir) {
+            shared_perm_add(dir, bits)?;
+            dir = match dir.parent() {
+                Some(p) => p,
+                None => break,
+            };
+        }
+
         return Ok(path);
     }
 
-    return Err(format!("Failed to create dir {}", path.display()));
+    Err(format!("Failed to create dir {}", path.display()))
+}
+
+// Where the loose ref `name` lives: refs/* under refs_dir, anything else
+// (HEAD, FETCH_HEAD, ...) directly in gitdir.
+fn ref_path(repo: &GitRepository, name: &str) -> PathBuf {
+    match name.strip_prefix("refs/") {
+        Some(rest) => repo.refs_dir().join(rest),
+        None => repo_path(repo, vec![name]),
+    }
+}
+
+// How many symbolic refs may be chained before we give up, as in git.
+const SYMREF_MAXDEPTH: usize = 5;
+
+fn ref_resolve(repo: &GitRepository, name: &str) -> Result<Option<String>, String> {
+    ref_resolve_depth(repo, name, 0)
+}
+
+fn ref_resolve_depth(repo: &GitRepository, name: &str, depth: usize) -> Result<Option<String>, String> {
+    if depth > SYMREF_MAXDEPTH {
+        return Err(format!("Symbolic ref {} nests too deeply", name));
+    }
+
+    let path = ref_path(repo, name);
+    if !path.is_file() {
+        let packed = ref_packed(repo)?;
+        return Ok(packed.into_iter().find(|r| r.name == name).map(|r| r.sha));
+    }
+
+    // Read lossily: a ref full of junk is broken, not a reason to fail.
+    let data = fs::read(&path).map_err(|e| e.to_string())?;
+    let data = String::from_utf8_lossy(&data);
+    let data = data.trim_end();
+    match data.strip_prefix("ref: ") {
+        Some(target) => ref_resolve_depth(repo, target, depth + 1),
+        None => Ok(Some(data.to_string())),
+    }
+}
+
+// A ref from packed-refs. `peeled` is the "^sha" line git writes after an
+// annotated tag.
+struct PackedRef {
+    name: String,
+    sha: String,
+    peeled: Option<String>,
+}
+
+fn ref_packed(repo: &GitRepository) -> Result<Vec<PackedRef>, String> {
+    let path = repo_path(repo, vec!["packed-refs"]);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let data = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    let mut refs: Vec<PackedRef> = Vec::new();
+    for line in data.lines().filter(|line| !line.starts_with('#')) {
+        if let Some(peeled) = line.strip_prefix('^') {
+            if let Some(last) = refs.last_mut() {
+                last.peeled = Some(peeled.to_string());
+            }
+        } else if let Some((sha, name)) = line.split_once(' ') {
+            refs.push(PackedRef {
+                name: name.to_string(),
+                sha: sha.to_string(),
+                peeled: None,
+            });
+        }
+    }
+    Ok(refs)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RefTarget {
+    Resolved(String),
+    // The ref doesn't lead to an object: holds what it contains instead, or
+    // why it couldn't be resolved.
+    Broken(String),
+}
+
+// Packed objects can't be read yet, so once a repository has packs a missing
+// loose object no longer proves a ref broken.
+fn ref_target(repo: &GitRepository, sha: String) -> RefTarget {
+    let is_sha = sha.len() == 40 && sha.bytes().all(|b| b.is_ascii_hexdigit());
+    if object_exists(repo, &sha) || (is_sha && repo_has_packs(repo)) {
+        RefTarget::Resolved(sha)
+    } else {
+        RefTarget::Broken(sha)
+    }
+}
+
+fn repo_has_packs(repo: &GitRepository) -> bool {
+    match fs::read_dir(repo.objects_dir().join("pack")) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some("idx".as_ref())),
+        Err(_) => false,
+    }
+}
+
+// Every ref under refs/ or in packed-refs that resolves to a value, sorted by
+// name; loose refs win over packed ones. A ref whose object is missing is
+// reported as broken instead of failing the listing.
+pub fn ref_list(repo: &GitRepository) -> Result<Vec<(String, RefTarget)>, String> {
+    let mut refs = Vec::new();
+    ref_collect(repo, &repo.refs_dir(), "refs", &mut refs)?;
+    for packed in ref_packed(repo)? {
+        if !refs.iter().any(|(n, _)| *n == packed.name) {
+            refs.push((packed.name, ref_target(repo, packed.sha)));
+        }
+    }
+    refs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(refs)
+}
+
+fn ref_collect(
+    repo: &GitRepository,
+    dir: &Path,
+    prefix: &str,
+    refs: &mut Vec<(String, RefTarget)>,
+) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            ref_collect(repo, &entry.path(), &name, refs)?;
+        } else {
+            match ref_resolve(repo, &name) {
+                Ok(Some(sha)) => refs.push((name, ref_target(repo, sha))),
+                Ok(None) => {}
+                Err(e) => refs.push((name, RefTarget::Broken(e))),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeadState {
+    OnBranch(String),
+    Detached(String),
+    // HEAD names a branch that has no commits yet.
+    Unborn(String),
 }
 
-fn repo_find(path: &str, required: bool) -> Result<GitRepository, String> {
-    let abs_path = fs::canonicalize(Path::new(path)).unwrap();
+pub fn head_state(repo: &GitRepository) -> Result<HeadState, String> {
+    let data = fs::read_to_string(repo_path(repo, vec!["HEAD"])).map_err(|e| e.to_string())?;
+    let data = data.trim_end();
+
+    match data.strip_prefix("ref: ") {
+        Some(target) => {
+            let name = target.strip_prefix("refs/heads/").unwrap_or(target).to_string();
+            if ref_resolve(repo, target)?.is_some() {
+                Ok(HeadState::OnBranch(name))
+            } else {
+                Ok(HeadState::Unborn(name))
+            }
+        }
+        None if data.len() == 40 && data.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Ok(HeadState::Detached(data.to_lowercase()))
+        }
+        None => Err(format!("Invalid HEAD {}", data)),
+    }
+}
+
+pub fn repo_find<P: AsRef<Path>>(path: P) -> Result<GitRepository, String> {
+    let abs_path = fs::canonicalize(path.as_ref()).map_err(|e| e.to_string())?;
 
     if abs_path.join(".git").is_dir() {
-        let repo = GitRepository::new(path, false)?;
-        return Ok(repo);
+        return GitRepository::new(abs_path, false);
+    }
+
+    match abs_path.parent() {
+        Some(p) => repo_find(p),
+        None => Err("Not a git repository (or any of the parent directories)".to_string()),
+    }
+}
+
+// Answers each of `options` ("git-dir", "show-toplevel" or
+// "is-inside-work-tree") in the order given, as git does.
+pub fn cmd_rev_parse(repo: &GitRepository, options: &[&str]) -> Result<(), String> {
+    for option in options {
+        match *option {
+            "git-dir" => println!("{}", repo.gitdir.display()),
+            "show-toplevel" => match &repo.worktree {
+                Some(worktree) => println!("{}", worktree.display()),
+                None => return Err("this operation must be run in a work tree".to_string()),
+            },
+            "is-inside-work-tree" => {
+                let cwd = fs::canonicalize(".").map_err(|e| e.to_string())?;
+                let inside = match &repo.worktree {
+                    Some(worktree) => cwd.starts_with(worktree) && !cwd.starts_with(&repo.gitdir),
+                    None => false,
+                };
+                println!("{}", inside);
+            }
+            _ => return Err(format!("Unknown rev-parse option {}", option)),
+        }
+    }
+
+    Ok(())
+}
+
+// How many peeled tags a repository remembers before it starts over.
+const PEEL_CACHE_MAX: usize = 1024;
+
+// What an annotated tag ultimately points at, or None if `sha` isn't a tag.
+// Objects never change, so peeled tags are remembered; the cache is only
+// cleared when it is full.
+fn ref_peel(repo: &GitRepository, sha: &str) -> Result<Option<String>, String> {
+    if let Some(peeled) = repo.peel_cache.borrow().get(sha) {
+        return Ok(Some(peeled.clone()));
+    }
+
+    let peeled = ref_peel_uncached(repo, sha)?;
+    if let Some(peeled) = &peeled {
+        let mut cache = repo.peel_cache.borrow_mut();
+        if cache.len() >= PEEL_CACHE_MAX {
+            cache.clear();
+        }
+        cache.insert(sha.to_string(), peeled.clone());
+    }
+    Ok(peeled)
+}
+
+// Packed objects can't be read, so peeling stops at the first one.
+fn ref_peel_uncached(repo: &GitRepository, sha: &str) -> Result<Option<String>, String> {
+    let mut peeled = None;
+    let mut current = sha.to_string();
+    loop {
+        if !object_exists(repo, &current) {
+            return Ok(peeled);
+        }
+        match object_read(repo, &current)? {
+            GitObjects::Tag(tag) => match tag.kvlm.get("object").first() {
+                Some(object) => current = String::from_utf8_lossy(object).to_string(),
+                None => return Err(format!("Tag {} has no object", current)),
+            },
+            _ => return Ok(peeled),
+        }
+        peeled = Some(current.clone());
+    }
+}
+
+pub fn cmd_ls_remote(remote: &str) -> Result<(), String> {
+    let path = remote.strip_prefix("file://").unwrap_or(remote);
+    if path.contains("://") {
+        return Err(format!("Unsupported remote {}: only local paths are supported", remote));
     }
 
-    if let Some(p) = abs_path.parent() {
-        return repo_find(path, required);
+    // Either a worktree, or the git directory itself (bare, or a path to .git).
+    let path = Path::new(path);
+    let repo = if path.join(".git").is_dir() {
+        GitRepository::new(path, false)?
+    } else if path.join("HEAD").is_file() {
+        GitRepository::with_gitdir(path)?
+    } else {
+        return Err(format!("Not a git repository {}", path.display()));
+    };
+    let mut refs = ref_list(&repo)?;
+    match ref_resolve(&repo, "HEAD") {
+        Ok(Some(sha)) => refs.insert(0, ("HEAD".to_string(), ref_target(&repo, sha))),
+        Ok(None) => {}
+        Err(e) => refs.insert(0, ("HEAD".to_string(), RefTarget::Broken(e))),
+    }
+    // packed-refs records peeled tags, which matters once the tags themselves
+    // are packed too.
+    let packed = ref_packed(&repo)?;
+
+    for (name, target) in refs {
+        match target {
+            RefTarget::Resolved(sha) => {
+                println!("{}\t{}", sha, name);
+                let peeled = match packed.iter().find(|r| r.name == name && r.sha == sha) {
+                    Some(r) if r.peeled.is_some() => r.peeled.clone(),
+                    _ => ref_peel(&repo, &sha)?,
+                };
+                if let Some(peeled) = peeled {
+                    println!("{}\t{}^{{}}", peeled, name);
+                }
+            }
+            RefTarget::Broken(_) => eprintln!("warning: ignoring broken ref {}", name),
+        }
+    }
+
+    Ok(())
+}
+
+// Reads `ls-tree` output ("<mode> <type> <sha>\t<path>" per line) and prints
+// the sha of the tree it describes, like `git mktree`.
+pub fn cmd_mktree(repo: &GitRepository, reader: &mut dyn BufRead) -> Result<(), String> {
+    println!("{}", mktree(repo, reader)?);
+    Ok(())
+}
+
+pub fn mktree(repo: &GitRepository, reader: &mut dyn BufRead) -> Result<String, String> {
+    let mut items = Vec::new();
+
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.is_empty() {
+            continue;
+        }
+        let bad_line = || format!("Malformed input line {}: {}", lineno + 1, line);
+
+        let (info, path) = line.split_once('\t').ok_or_else(bad_line)?;
+        let info: Vec<&str> = info.split(' ').collect();
+        if info.len() != 3 || path.is_empty() || path.contains('/') {
+            return Err(bad_line());
+        }
+
+        let mode = u32::from_str_radix(info[0], 8).map_err(|_| bad_line())?;
+        let fmt = match mode {
+            0o040000 => "tree",
+            0o160000 => "commit",
+            _ => "blob",
+        };
+        if info[1] != fmt {
+            return Err(format!("Entry {} has mode {} but type {}", path, info[0], info[1]));
+        }
+
+        let sha = GitTreeLeaf::sha_from_hex(info[2]).ok_or_else(bad_line)?;
+        // Gitlinks point into another repository, so only check the rest.
+        if fmt != "commit" {
+            let kind = object_type(repo, info[2])
+                .map_err(|_| format!("Entry {} object {} is unavailable", path, info[2]))?;
+            if kind != fmt {
+                return Err(format!(
+                    "Entry {} object {} is a {} but specified type was ({})",
+                    path, info[2], kind, fmt
+                ));
+            }
+        }
+
+        items.push(GitTreeLeaf {
+            mode: format!("{:o}", mode),
+            path: path.as_bytes().to_vec(),
+            sha,
+        });
     }
 
-    return Err("Not a git repository".to_string());
+    object_write(repo, &GitTree { items }, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hagakure-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn temp_repo(name: &str) -> GitRepository {
+        GitRepository::repo_create(temp_path(name).to_str().unwrap(), None).unwrap()
+    }
+
+    fn kvlm(headers: &[(&str, &str)], message: &str) -> Kvlm {
+        Kvlm {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            message: message.as_bytes().to_vec(),
+        }
+    }
+
+    // An empty tree, a commit of it, and an annotated tag v1 of that commit.
+    fn repo_with_tagged_commit(name: &str) -> (GitRepository, String, String, String) {
+        let repo = temp_repo(name);
+        let tree = object_write(&repo, &GitTree { items: Vec::new() }, true).unwrap();
+        let ident = "A U Thor <author@example.com> 1600000000 +0000";
+        let commit = GitCommit {
+            kvlm: kvlm(&[("tree", &tree), ("author", ident), ("committer", ident)], "first\n"),
+        };
+        let commit = object_write(&repo, &commit, true).unwrap();
+        let tag = GitTag {
+            kvlm: kvlm(
+                &[("object", &commit), ("type", "commit"), ("tag", "v1"), ("tagger", ident)],
+                "release\n",
+            ),
+        };
+        let tag = object_write(&repo, &tag, true).unwrap();
+        fs::write(repo.gitdir.join("refs/tags/v1"), format!("{}\n", tag)).unwrap();
+        (repo, tree, commit, tag)
+    }
+
+    #[test]
+    fn ref_peel_remembers_peeled_tags() {
+        let (repo, _, commit, tag) = repo_with_tagged_commit("peel-cache");
+        assert_eq!(ref_peel(&repo, &tag), Ok(Some(commit.clone())));
+
+        // Peeling again must not read the tag: it's gone from the store now.
+        fs::remove_file(repo.objects_dir().join(&tag[0..2]).join(&tag[2..])).unwrap();
+        assert_eq!(ref_peel(&repo, &tag), Ok(Some(commit.clone())));
+        assert_eq!(ref_peel(&repo, &commit), Ok(None));
+    }
+
+    #[test]
+    fn ref_peel_cache_is_bounded() {
+        let (repo, _, commit, tag) = repo_with_tagged_commit("peel-cache-full");
+        for i in 0..PEEL_CACHE_MAX {
+            repo.peel_cache.borrow_mut().insert(format!("{:040}", i), commit.clone());
+        }
+
+        assert_eq!(ref_peel(&repo, &tag), Ok(Some(commit)));
+        assert_eq!(repo.peel_cache.borrow().len(), 1);
+    }
+
+    #[test]
+    fn object_find_returns_owned_sha_for_ref_name() {
+        let (repo, _, commit, _) = repo_with_tagged_commit("find-owned");
+        fs::write(repo.gitdir.join("refs/heads/master"), format!("{}\n", commit)).unwrap();
+
+        let name = String::from("master");
+        let sha = object_find(&repo, &name, None, false).unwrap();
+        drop(name);
+
+        assert_eq!(sha, commit);
+        assert_eq!(sha.len(), 40);
+        assert_eq!(object_find(&repo, "HEAD", None, false), Ok(commit));
+    }
+
+    #[test]
+    fn object_find_follows_commit_to_tree() {
+        let (repo, tree, commit, _) = repo_with_tagged_commit("find-commit-tree");
+
+        assert_eq!(object_find(&repo, &commit, Some("tree"), true), Ok(tree));
+        assert!(object_find(&repo, &commit, Some("tree"), false).is_err());
+        assert_eq!(object_find(&repo, &commit, Some("commit"), true), Ok(commit));
+    }
+
+    #[test]
+    fn object_find_peels_annotated_tag_to_tree() {
+        let (repo, tree, commit, tag) = repo_with_tagged_commit("find-tag-tree");
+
+        assert_eq!(object_find(&repo, "v1", Some("tree"), true), Ok(tree));
+        assert_eq!(object_find(&repo, "v1", Some("commit"), true), Ok(commit));
+        assert_eq!(object_find(&repo, "v1", Some("tag"), true), Ok(tag));
+        assert!(object_find(&repo, "v1", Some("blob"), true).is_err());
+    }
+
+    // Passes writes through to `file`, counting the syncs asked for.
+    struct RecordingFile<'a> {
+        file: fs::File,
+        syncs: &'a std::cell::Cell<usize>,
+    }
+
+    impl<'a> Write for RecordingFile<'a> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl<'a> SyncWrite for RecordingFile<'a> {
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            self.file.sync_all()
+        }
+    }
+
+    // Stores a blob through RecordingFile and returns how often it synced.
+    fn object_store_syncs(repo: &GitRepository) -> usize {
+        let syncs = std::cell::Cell::new(0);
+        let mut raw = Vec::new();
+        let sha = object_hash(&GitBlob { blobdata: b"durable\n".to_vec() }, &mut raw);
+        let dir = dir_ensure(repo, repo.objects_dir().join(&sha[0..2]), true).unwrap();
+        let path = dir.join(&sha[2..]);
+        object_store_with(repo, &path, &object_compress(&raw).unwrap(), |tmp| {
+            fs::File::create(tmp).map(|file| RecordingFile { file, syncs: &syncs })
+        })
+        .unwrap();
+
+        assert!(path.is_file());
+        syncs.get()
+    }
+
+    #[test]
+    fn object_store_syncs_only_with_fsync_object_files() {
+        let mut repo = temp_repo("fsync-unset");
+        assert_eq!(object_store_syncs(&repo), 0);
+
+        repo = temp_repo("fsync-false");
+        repo.conf.with_section(Some("core")).set("fsyncObjectFiles", "false");
+        assert_eq!(object_store_syncs(&repo), 0);
+
+        repo = temp_repo("fsync-true");
+        repo.conf.with_section(Some("core")).set("fsyncObjectFiles", "true");
+        assert_eq!(object_store_syncs(&repo), 1);
+    }
+
+    #[test]
+    fn object_write_leaves_existing_objects_alone() {
+        let repo = temp_repo("write-existing");
+        let blob = GitBlob { blobdata: b"hello\n".to_vec() };
+
+        let sha = object_write(&repo, &blob, true).unwrap();
+        assert_eq!(sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+        let dir = repo.objects_dir().join(&sha[0..2]);
+        let path = dir.join(&sha[2..]);
+        fs::write(&path, b"marker").unwrap();
+
+        object_write(&repo, &blob, true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"marker");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn repo_find_walks_up_from_nested_directory() {
+        let path = temp_path("find");
+        GitRepository::repo_create(path.to_str().unwrap(), None).unwrap();
+        let nested = path.join("a/b/c");
+        fs::create_dir_all(&nested).unwrap();
+        let worktree = fs::canonicalize(&path).unwrap();
+
+        let repo = repo_find(&nested).unwrap();
+        assert_eq!(repo.worktree, Some(worktree.clone()));
+        assert_eq!(repo.gitdir, worktree.join(".git"));
+        assert_eq!(repo_find(worktree.join(".git/objects")).unwrap().worktree, Some(worktree));
+    }
+
+    #[test]
+    fn ref_list_reports_broken_refs_and_keeps_listing() {
+        let (repo, _, commit, _) = repo_with_tagged_commit("ref-list-broken");
+        let heads = repo.refs_dir().join("heads");
+        fs::write(heads.join("master"), format!("{}\n", commit)).unwrap();
+        fs::write(heads.join("bogus"), format!("{}\n", "0".repeat(40))).unwrap();
+        fs::write(heads.join("junk"), b"\xff\xfe not a sha\n").unwrap();
+
+        let refs = ref_list(&repo).unwrap();
+        let names: Vec<&str> = refs.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["refs/heads/bogus", "refs/heads/junk", "refs/heads/master", "refs/tags/v1"]);
+        assert_eq!(refs[0].1, RefTarget::Broken("0".repeat(40)));
+        assert!(matches!(refs[1].1, RefTarget::Broken(_)));
+        assert_eq!(refs[2].1, RefTarget::Resolved(commit));
+        assert!(matches!(refs[3].1, RefTarget::Resolved(_)));
+    }
+
+    #[test]
+    fn ref_resolve_reads_packed_refs() {
+        let repo = temp_repo("packed-refs");
+        let sha = "ce013625030ba8dba906f756967f9e9ca394464a";
+        let peeled = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        fs::write(
+            repo.gitdir.join("packed-refs"),
+            format!("# pack-refs with: peeled fully-peeled sorted\n{} refs/tags/v1\n^{}\n", sha, peeled),
+        )
+        .unwrap();
+
+        assert_eq!(ref_resolve(&repo, "refs/tags/v1"), Ok(Some(sha.to_string())));
+        assert_eq!(ref_resolve(&repo, "refs/tags/v2"), Ok(None));
+    }
+
+    #[test]
+    fn ref_resolve_rejects_symref_loops() {
+        let repo = temp_repo("symref-loop");
+        fs::write(repo.gitdir.join("refs/heads/master"), "ref: refs/heads/master\n").unwrap();
+
+        fs::write(repo.gitdir.join("refs/heads/side"), format!("{}\n", "a".repeat(40))).unwrap();
+        assert!(ref_resolve(&repo, "HEAD").unwrap_err().contains("nests too deeply"));
+
+        // The loop breaks its own ref only; the rest still lists.
+        let refs = ref_list(&repo).unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].0, "refs/heads/master");
+        assert!(matches!(&refs[0].1, RefTarget::Broken(e) if e.contains("nests too deeply")));
+        assert_eq!(refs[1], ("refs/heads/side".to_string(), RefTarget::Broken("a".repeat(40))));
+    }
+
+    #[test]
+    fn head_state_reports_unborn_branch() {
+        let repo = temp_repo("head-unborn");
+        assert_eq!(head_state(&repo), Ok(HeadState::Unborn("master".to_string())));
+    }
+
+    #[test]
+    fn head_state_reports_current_branch() {
+        let repo = temp_repo("head-branch");
+        let sha = object_write(&repo, &GitBlob { blobdata: b"hello\n".to_vec() }, true).unwrap();
+        fs::write(repo.gitdir.join("refs/heads/master"), format!("{}\n", sha)).unwrap();
+        assert_eq!(head_state(&repo), Ok(HeadState::OnBranch("master".to_string())));
+    }
+
+    #[test]
+    fn head_state_reports_detached_sha() {
+        let repo = temp_repo("head-detached");
+        let sha = object_write(&repo, &GitBlob { blobdata: b"hello\n".to_vec() }, true).unwrap();
+        fs::write(repo.gitdir.join("HEAD"), format!("{}\n", sha)).unwrap();
+        assert_eq!(head_state(&repo), Ok(HeadState::Detached(sha)));
+
+        fs::write(repo.gitdir.join("HEAD"), "garbage\n").unwrap();
+        assert!(head_state(&repo).is_err());
+    }
+
+    #[test]
+    fn mktree_writes_tree_from_ls_tree_lines() {
+        let repo = temp_repo("mktree");
+        let hello = object_write(&repo, &GitBlob { blobdata: b"hello\n".to_vec() }, true).unwrap();
+        let world = object_write(&repo, &GitBlob { blobdata: b"world\n".to_vec() }, true).unwrap();
+        // Out of order on purpose: the tree must come out sorted, as git's does.
+        let input = format!("100755 blob {}\tworld.sh\n100644 blob {}\thello.txt\n", world, hello);
+
+        let sha = mktree(&repo, &mut input.as_bytes()).unwrap();
+        // What `git mktree` prints for the same input.
+        assert_eq!(sha, "a6d72a8d5edb4fa4339aa56d9c449e9ace47b62d");
+        let items = match object_read(&repo, &sha).unwrap() {
+            GitObjects::Tree(tree) => tree.items,
+            _ => panic!("{} is not a tree", sha),
+        };
+        let entries: Vec<(&str, &[u8], String)> = items
+            .iter()
+            .map(|leaf| (leaf.mode.as_str(), leaf.path.as_slice(), leaf.sha_hex()))
+            .collect();
+        assert_eq!(
+            entries,
+            [("100644", &b"hello.txt"[..], hello), ("100755", &b"world.sh"[..], world)]
+        );
+    }
+
+    #[test]
+    fn mktree_rejects_object_of_wrong_type() {
+        let repo = temp_repo("mktree-type");
+        let tree = object_write(&repo, &GitTree { items: Vec::new() }, true).unwrap();
+        let input = format!("100644 blob {}\tempty\n", tree);
+
+        assert_eq!(
+            mktree(&repo, &mut input.as_bytes()),
+            Err(format!("Entry empty object {} is a tree but specified type was (blob)", tree))
+        );
+    }
+
+    #[test]
+    fn batch_writer_writes_every_blob() {
+        let repo = temp_repo("batch");
+        let mut batch = BatchWriter::new(&repo);
+        let shas: Vec<String> = (0..100)
+            .map(|i| batch.add(&GitBlob { blobdata: format!("blob {}\n", i).into_bytes() }).unwrap())
+            .collect();
+        assert!(!object_exists(&repo, &shas[0]));
+        batch.flush().unwrap();
+
+        for (i, sha) in shas.iter().enumerate() {
+            match object_read(&repo, sha).unwrap() {
+                GitObjects::Blob(blob) => assert_eq!(blob.blobdata, format!("blob {}\n", i).into_bytes()),
+                _ => panic!("{} is not a blob", sha),
+            }
+        }
+    }
+
+    #[test]
+    fn batch_writer_flushes_on_drop() {
+        let repo = temp_repo("batch-drop");
+        let sha = {
+            let mut batch = BatchWriter::new(&repo);
+            batch.add(&GitBlob { blobdata: b"hello\n".to_vec() }).unwrap()
+        };
+        assert!(object_exists(&repo, &sha));
+    }
+
+    #[test]
+    fn repo_dirs_for_normal_and_bare_repos() {
+        let path = temp_path("dirs");
+        let repo = GitRepository::repo_create(path.to_str().unwrap(), None).unwrap();
+        let gitdir = fs::canonicalize(&path).unwrap().join(".git");
+        fs::write(repo.refs_dir().join("heads/master"), format!("{}\n", "a".repeat(40))).unwrap();
+        assert_eq!(fs::canonicalize(repo.objects_dir()).unwrap(), gitdir.join("objects"));
+        assert_eq!(fs::canonicalize(repo.refs_dir()).unwrap(), gitdir.join("refs"));
+
+        // A bare layout: the git directory on its own, with core.bare set.
+        let bare = temp_path("dirs.git");
+        fs::rename(&gitdir, &bare).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        fs::write(bare.join("config"), "[core]\n\trepositoryformatversion = 0\n\tbare = true\n").unwrap();
+        let bare = fs::canonicalize(&bare).unwrap();
+        let repo = GitRepository::with_gitdir(&bare).unwrap();
+        assert_eq!(repo.worktree, None);
+        assert_eq!(repo.objects_dir(), bare.join("objects"));
+        assert_eq!(repo.refs_dir(), bare.join("refs"));
+        assert_eq!(ref_resolve(&repo, "HEAD").unwrap(), Some("a".repeat(40)));
+    }
+
+    #[test]
+    fn object_read_limited_rejects_large_blob() {
+        let repo = temp_repo("read-limited");
+        let sha = object_write(&repo, &GitBlob { blobdata: vec![b'x'; 1000] }, true).unwrap();
+
+        assert!(object_read_limited(&repo, &sha, 1000).is_ok());
+        let err = object_read_limited(&repo, &sha, 999).err().unwrap();
+        assert!(err.contains("over the limit"), "{}", err);
+    }
+
+    #[test]
+    fn object_read_limited_aborts_on_header() {
+        // The header claims 100000 bytes but only a few follow, so reaching
+        // the size check proves nothing past the header was inflated.
+        let repo = temp_repo("read-limited-header");
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 100000\x00short").unwrap();
+        let dir = repo.objects_dir().join(&sha[0..2]);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(&sha[2..]), encoder.finish().unwrap()).unwrap();
+
+        let err = object_read_limited(&repo, sha, 1024).err().unwrap();
+        assert_eq!(
+            err,
+            format!("Object {} is 100000 bytes, over the limit of 1024 bytes", sha)
+        );
+    }
+
+    #[test]
+    fn object_read_limited_rejects_non_sha_names() {
+        let repo = temp_repo("read-limited-name");
+        for name in ["../../config", "abc", "g123456789abcdef0123456789abcdef01234567"].iter() {
+            let err = object_read_limited(&repo, name, 1024).err().unwrap();
+            assert_eq!(err, format!("Invalid object name {}", name));
+        }
+    }
+
+    #[test]
+    fn mergetag_with_blank_lines_round_trips() {
+        // Blank lines inside the embedded tag are written as a lone space.
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+parent 1111111111111111111111111111111111111111\n\
+parent 2222222222222222222222222222222222222222\n\
+author A U Thor <author@example.com> 1600000000 +0900\n\
+committer A U Thor <author@example.com> 1600000000 +0900\n\
+mergetag object 2222222222222222222222222222222222222222\n \
+type commit\n \
+tag v1\n \
+tagger A U Thor <author@example.com> 1600000000 +0900\n \
+\n \
+Release v1\n \
+-----BEGIN PGP SIGNATURE-----\n \
+\n \
+iQEzBAABCAAdFiEE\n \
+-----END PGP SIGNATURE-----\n\
+\n\
+Merge tag 'v1'\n";
+
+        let mut commit = GitCommit { kvlm: Kvlm::default() };
+        commit.deserialize(raw.to_vec()).unwrap();
+        assert_eq!(
+            commit.mergetags(),
+            vec!["object 2222222222222222222222222222222222222222\n\
+type commit\n\
+tag v1\n\
+tagger A U Thor <author@example.com> 1600000000 +0900\n\
+\n\
+Release v1\n\
+-----BEGIN PGP SIGNATURE-----\n\
+\n\
+iQEzBAABCAAdFiEE\n\
+-----END PGP SIGNATURE-----"]
+        );
+        assert_eq!(commit.serialize(), raw.to_vec());
+    }
+
+    fn tree_entry(mode: &str, path: &[u8], sha: u8) -> Vec<u8> {
+        let mut raw = format!("{} ", mode).into_bytes();
+        raw.extend_from_slice(path);
+        raw.push(0);
+        raw.extend_from_slice(&[sha; 20]);
+        raw
+    }
+
+    #[test]
+    fn tree_parse_reports_offset_of_truncated_sha() {
+        let mut raw = tree_entry("100644", b"a.txt", 0xaa);
+        let second = raw.len();
+        raw.extend_from_slice(&tree_entry("100644", b"b.txt", 0xbb)[..20]);
+
+        let err = tree_parse(&raw).unwrap_err();
+        // "100644 b.txt\0" is 13 bytes, so the sha starts right after it.
+        assert_eq!(err.offset, second + 13);
+        assert_eq!(err.reason, "truncated sha (7 of 20 bytes)");
+        assert_eq!(err.entries.len(), 1);
+        assert_eq!(err.entries[0].path, b"a.txt");
+        assert!(err.to_string().contains(&format!("at byte {}", second + 13)));
+    }
+
+    #[test]
+    fn tree_parse_keeps_non_utf8_paths() {
+        let raw = tree_entry("100644", b"caf\xe9", 0xcc);
+        let entries = tree_parse(&raw).unwrap();
+        assert_eq!(entries[0].path, b"caf\xe9");
+        assert_eq!(GitTree { items: entries }.serialize(), raw);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_init_makes_object_dirs_group_writable() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode();
+
+        let path = temp_path("shared-init");
+        let repo = GitRepository::repo_create(path.to_str().unwrap(), Some("group")).unwrap();
+        // What `git init --shared=group` writes.
+        assert_eq!(
+            fs::read_to_string(repo.gitdir.join("config")).unwrap(),
+            "[core]\n\
+             \trepositoryformatversion = 0\n\
+             \tfilemode = true\n\
+             \tbare = false\n\
+             \tlogallrefupdates = true\n\
+             \tsharedrepository = 1\n\
+             [receive]\n\
+             \tdenyNonFastforwards = true\n"
+        );
+
+        let sha = object_write(&repo, &GitBlob { blobdata: b"shared\n".to_vec() }, true).unwrap();
+        let dir = repo.objects_dir().join(&sha[0..2]);
+        assert_eq!(mode(&dir) & 0o2070, 0o2070);
+        assert_eq!(mode(&repo.objects_dir()) & 0o070, 0o070);
+        assert_eq!(mode(&dir.join(&sha[2..])) & 0o040, 0o040);
+        for file in ["HEAD", "config", "description"].iter() {
+            assert_eq!(mode(&repo.gitdir.join(file)) & 0o060, 0o060, "{}", file);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn init_writes_git_default_config() {
+        let repo = temp_repo("default-config");
+
+        assert_eq!(
+            fs::read_to_string(repo.gitdir.join("config")).unwrap(),
+            "[core]\n\
+             \trepositoryformatversion = 0\n\
+             \tfilemode = true\n\
+             \tbare = false\n\
+             \tlogallrefupdates = true\n"
+        );
+    }
+
+    #[test]
+    fn config_values_are_escaped() {
+        assert_eq!(config_escape("plain value"), "plain value");
+        assert_eq!(config_escape("a\"b\\c\td\n"), "a\\\"b\\\\c\\td\\n");
+        assert_eq!(config_escape("x # y"), "\"x # y\"");
+        assert_eq!(config_escape(" padded"), "\" padded\"");
+    }
 }
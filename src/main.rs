@@ -1,15 +1,106 @@
 #[macro_use]
 extern crate clap;
-use crate::lib::GitRepository;
-use clap::App;
+use clap::{App, Arg, SubCommand};
+use hagakure::{cmd_ls_remote, cmd_mktree, cmd_rev_parse, repo_find, GitRepository};
+use std::{fmt, io, process};
 
-mod lib;
+// Anything that stops a command. main prints it and exits with `status`:
+// 128 when there is no repository to work on, as in git, and 1 otherwise.
+#[derive(Debug)]
+struct HagakureError {
+    message: String,
+    status: i32,
+}
+
+impl HagakureError {
+    fn no_repository(message: String) -> HagakureError {
+        HagakureError { message, status: 128 }
+    }
+}
+
+impl From<String> for HagakureError {
+    fn from(message: String) -> HagakureError {
+        HagakureError { message, status: 1 }
+    }
+}
+
+impl fmt::Display for HagakureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fatal: {}", self.message)
+    }
+}
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(e.status);
+    }
+}
+
+// Declared here rather than in cli.yml: `--shared[=<permissions>]` needs
+// require_equals, which clap's YAML loader doesn't know about.
+fn init_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("init")
+        .about("Initialize a new, empty repository")
+        .arg(
+            Arg::with_name("path")
+                .help("Where to create a repository")
+                .default_value("."),
+        )
+        .arg(
+            Arg::with_name("shared")
+                .long("shared")
+                .help("Make the repository group-shared (group) or world-readable (all)")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("permissions"),
+        )
+}
+
+fn run() -> Result<(), HagakureError> {
     let yaml = load_yaml!("cli.yml");
-    let matches = App::from_yaml(yaml).get_matches();
+    let matches = App::from_yaml(yaml).subcommand(init_subcommand()).get_matches();
     if let Some(matches) = matches.subcommand_matches("init") {
         let path = matches.value_of("path").unwrap();
-        GitRepository::repo_create(path).unwrap();
+        let shared = if matches.is_present("shared") {
+            Some(matches.value_of("shared").unwrap_or("group"))
+        } else {
+            None
+        };
+        GitRepository::repo_create(path, shared)?;
+        return Ok(());
+    } else if let Some(matches) = matches.subcommand_matches("ls-remote") {
+        cmd_ls_remote(matches.value_of("repository").unwrap())?;
+        return Ok(());
     }
+
+    // Everything else works on the repository we're in.
+    let (name, sub_matches) = matches.subcommand();
+    let sub_matches = match sub_matches {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    let repo = match matches.value_of("git-dir") {
+        Some(gitdir) => GitRepository::with_gitdir(gitdir),
+        None => repo_find("."),
+    }
+    .map_err(HagakureError::no_repository)?;
+
+    match name {
+        "rev-parse" => {
+            // Answered in the order given on the command line, as git does.
+            let mut options: Vec<(usize, &str)> = ["git-dir", "show-toplevel", "is-inside-work-tree"]
+                .iter()
+                .filter_map(|option| sub_matches.index_of(option).map(|i| (i, *option)))
+                .collect();
+            options.sort();
+            let options: Vec<&str> = options.into_iter().map(|(_, option)| option).collect();
+            cmd_rev_parse(&repo, &options)?
+        }
+        "mktree" => cmd_mktree(&repo, &mut io::stdin().lock())?,
+        _ => unreachable!(),
+    }
+
+    Ok(())
 }
@@ -0,0 +1,203 @@
+use hagakure::{object_write, GitCommit, GitRepository, GitTag, GitTree, Kvlm};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("hagakure-cli-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+fn hagakure(cwd: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hagakure"))
+        .args(args)
+        .current_dir(cwd)
+        .env_remove("GIT_DIR")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+// A repository at `path` whose master branch holds one commit.
+fn repo_with_commit(path: &Path) -> (GitRepository, String) {
+    let repo = GitRepository::repo_create(path.to_str().unwrap(), None).unwrap();
+    let tree = object_write(&repo, &GitTree { items: Vec::new() }, true).unwrap();
+    let ident = b"A U Thor <author@example.com> 1600000000 +0000".to_vec();
+    let commit = GitCommit {
+        kvlm: Kvlm {
+            headers: vec![
+                ("tree".to_string(), tree.into_bytes()),
+                ("author".to_string(), ident.clone()),
+                ("committer".to_string(), ident),
+            ],
+            message: b"first\n".to_vec(),
+        },
+    };
+    let commit = object_write(&repo, &commit, true).unwrap();
+    fs::write(repo.gitdir.join("refs/heads/master"), format!("{}\n", commit)).unwrap();
+    (repo, commit)
+}
+
+#[test]
+fn ls_remote_lists_branches_and_tags_of_local_remote() {
+    let path = temp_path("ls-remote");
+    let (repo, commit) = repo_with_commit(&path);
+    // v1 is only in packed-refs, as after `git pack-refs --all`; v2 is an
+    // annotated tag, listed with its peeled target like git does.
+    fs::write(repo.gitdir.join("packed-refs"), format!("{} refs/tags/v1\n", commit)).unwrap();
+    let tag = GitTag {
+        kvlm: Kvlm {
+            headers: vec![
+                ("object".to_string(), commit.clone().into_bytes()),
+                ("type".to_string(), b"commit".to_vec()),
+                ("tag".to_string(), b"v2".to_vec()),
+            ],
+            message: b"release\n".to_vec(),
+        },
+    };
+    let tag = object_write(&repo, &tag, true).unwrap();
+    fs::write(repo.gitdir.join("refs/tags/v2"), format!("{}\n", tag)).unwrap();
+    let expected = format!(
+        "{c}\tHEAD\n{c}\trefs/heads/master\n{c}\trefs/tags/v1\n{t}\trefs/tags/v2\n{c}\trefs/tags/v2^{{}}\n",
+        c = commit,
+        t = tag
+    );
+
+    let output = hagakure(&std::env::temp_dir(), &["ls-remote", path.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), expected);
+
+    // The git directory itself works too, whether named .git or bare.
+    let output = hagakure(&std::env::temp_dir(), &["ls-remote", repo.gitdir.to_str().unwrap()]);
+    assert_eq!(stdout(&output), expected);
+
+    let bare = temp_path("ls-remote-bare.git");
+    fs::rename(&repo.gitdir, &bare).unwrap();
+    let output = hagakure(&std::env::temp_dir(), &["ls-remote", bare.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), expected);
+}
+
+#[test]
+fn ls_remote_lists_refs_of_packed_repository() {
+    let path = temp_path("ls-remote-packed");
+    let (repo, commit) = repo_with_commit(&path);
+    let tag = "fbea445be0d2b7fbc8191ecb35b328fe4be32704";
+    // As after `git gc`: every ref in packed-refs, every object in a pack.
+    fs::write(
+        repo.gitdir.join("packed-refs"),
+        format!(
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             {c} refs/heads/master\n{c} refs/heads/side\n{t} refs/tags/v1\n^{c}\n",
+            c = commit,
+            t = tag
+        ),
+    )
+    .unwrap();
+    fs::remove_file(repo.gitdir.join("refs/heads/master")).unwrap();
+    for entry in fs::read_dir(repo.objects_dir()).unwrap() {
+        fs::remove_dir_all(entry.unwrap().path()).unwrap();
+    }
+    let pack = repo.objects_dir().join("pack");
+    fs::create_dir_all(&pack).unwrap();
+    fs::write(pack.join("pack-1.idx"), "").unwrap();
+    fs::write(pack.join("pack-1.pack"), "").unwrap();
+
+    let output = hagakure(&std::env::temp_dir(), &["ls-remote", path.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stderr(&output), "");
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{c}\tHEAD\n{c}\trefs/heads/master\n{c}\trefs/heads/side\n{t}\trefs/tags/v1\n{c}\trefs/tags/v1^{{}}\n",
+            c = commit,
+            t = tag
+        )
+    );
+}
+
+#[test]
+fn rev_parse_from_nested_subdirectory() {
+    let path = temp_path("rev-parse");
+    GitRepository::repo_create(path.to_str().unwrap(), None).unwrap();
+    let nested = path.join("a/b/c");
+    fs::create_dir_all(&nested).unwrap();
+    let worktree = fs::canonicalize(&path).unwrap();
+
+    let output = hagakure(&nested, &["rev-parse", "--git-dir"]);
+    assert_eq!(stdout(&output), format!("{}\n", worktree.join(".git").display()));
+    let output = hagakure(&nested, &["rev-parse", "--show-toplevel"]);
+    assert_eq!(stdout(&output), format!("{}\n", worktree.display()));
+    let output = hagakure(&nested, &["rev-parse", "--is-inside-work-tree"]);
+    assert_eq!(stdout(&output), "true\n");
+
+    // Several at once are answered in the order asked.
+    let output = hagakure(&nested, &["rev-parse", "--show-toplevel", "--git-dir"]);
+    assert_eq!(
+        stdout(&output),
+        format!("{}\n{}\n", worktree.display(), worktree.join(".git").display())
+    );
+
+    // Inside .git we're in the repository but not in its work tree.
+    let output = hagakure(&worktree.join(".git/refs"), &["rev-parse", "--is-inside-work-tree"]);
+    assert_eq!(stdout(&output), "false\n");
+}
+
+#[test]
+fn git_dir_uses_current_directory_as_worktree() {
+    let path = temp_path("git-dir");
+    repo_with_commit(&path);
+    let elsewhere = temp_path("git-dir-elsewhere");
+    fs::create_dir_all(&elsewhere).unwrap();
+    let gitdir = path.join(".git");
+
+    let args = ["--git-dir", gitdir.to_str().unwrap(), "rev-parse", "--show-toplevel"];
+    let output = hagakure(&elsewhere, &args);
+    let cwd = fs::canonicalize(&elsewhere).unwrap();
+    assert_eq!(stdout(&output), format!("{}\n", cwd.display()));
+
+    // A bare repository has no worktree at all.
+    fs::write(gitdir.join("config"), "[core]\n\trepositoryformatversion = 0\n\tbare = true\n").unwrap();
+    let args = ["--git-dir", gitdir.to_str().unwrap(), "rev-parse", "--is-inside-work-tree"];
+    assert_eq!(stdout(&hagakure(&elsewhere, &args)), "false\n");
+    let args = ["--git-dir", gitdir.to_str().unwrap(), "rev-parse", "--show-toplevel"];
+    let output = hagakure(&elsewhere, &args);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "fatal: this operation must be run in a work tree\n");
+}
+
+#[test]
+fn repo_command_outside_repository_fails() {
+    let path = temp_path("no-repo");
+    fs::create_dir_all(&path).unwrap();
+
+    let output = hagakure(&path, &["rev-parse", "--git-dir"]);
+    assert_eq!(output.status.code(), Some(128));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "fatal: Not a git repository (or any of the parent directories)\n"
+    );
+}
+
+#[test]
+fn init_on_non_empty_directory_reports_error() {
+    let path = temp_path("init-non-empty");
+    fs::create_dir_all(&path).unwrap();
+    fs::write(path.join("file"), "").unwrap();
+
+    let output = hagakure(&path, &["init", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), format!("fatal: {} is not empty!\n", path.display()));
+    assert!(!path.join(".git").exists());
+}